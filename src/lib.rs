@@ -0,0 +1,15 @@
+//! A virtual filesystem abstraction, mirroring `std::fs` but pluggable
+//! across backends (in-memory, physical disk, ...).
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
+
+mod vfs;
+pub mod memory;
+pub mod builder;
+pub mod overlay;
+
+pub use vfs::{VFS, VPath, VMetadata, RemoveOptions, RenameOptions, CopyOptions, FsEvent, FsEventKind};
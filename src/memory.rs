@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf, Component, Components};
+use std::fmt;
 use std::fmt::Debug;
 use std::io::{Read, Write, Seek, SeekFrom, Result};
 use std::io::{Error, ErrorKind};
@@ -6,14 +7,17 @@ use std::io::{Error, ErrorKind};
 use std::cell::RefCell;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::ops::{Deref, DerefMut};
+use std::sync::mpsc;
+use std::sync::mpsc::{Sender, Receiver};
+use std::ops::DerefMut;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::hash_map::Entry;
 
 use std::cmp;
 
-use vfs::{VFS, VPath, VMetadata};
+use vfs::{VFS, VPath, VMetadata, RemoveOptions, RenameOptions, CopyOptions, FsEvent, FsEventKind};
 
 pub type Filename = String;
 
@@ -26,6 +30,42 @@ impl DataHandle {
     }
 }
 
+/// Positioned, cursor-free access to a file's bytes: every call stands on
+/// its own, so any number of readers can read the same handle at once;
+/// a positioned writer holds the exclusive lock only for its own call.
+pub trait RandomAccess {
+    /// Read into `buf` starting at `offset`, returning the number of bytes
+    /// actually read. `offset` is clamped to the length of the data, and at
+    /// most `min(buf.len(), len - offset)` bytes are copied.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    /// Write `buf` at `offset`, growing the underlying buffer if necessary.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize>;
+}
+
+impl RandomAccess for DataHandle {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let data = self.0.read().unwrap();
+        let offset = cmp::min(offset as usize, data.len());
+        let available = &data[offset..];
+        let n = cmp::min(available.len(), buf.len());
+        buf[..n].clone_from_slice(&available[..n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut data = self.0.write().unwrap();
+        let offset = offset as usize;
+        if data.len() < offset {
+            data.resize(offset, 0);
+        }
+        let space = data.len() - offset;
+        let (left, right) = buf.split_at(cmp::min(space, buf.len()));
+        data[offset..offset + left.len()].clone_from_slice(left);
+        data.extend_from_slice(right);
+        Ok(buf.len())
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum NodeKind {
     Directory,
@@ -64,9 +104,20 @@ impl FsNode {
     }
 }
 
-#[derive(Debug)]
 pub struct MemoryFSImpl {
     root: FsNode,
+    locked: HashSet<String>,
+    watchers: Vec<(String, Sender<FsEvent>)>,
+}
+
+impl fmt::Debug for MemoryFSImpl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryFSImpl")
+         .field("root", &self.root)
+         .field("locked", &self.locked)
+         .field("watchers", &self.watchers.iter().map(|&(ref prefix, _)| prefix).collect::<Vec<_>>())
+         .finish()
+    }
 }
 
 pub type MemoryFSHandle = Arc<RwLock<MemoryFSImpl>>;
@@ -79,7 +130,21 @@ pub struct MemoryFS {
 
 impl MemoryFS {
     pub fn new() -> MemoryFS {
-        MemoryFS { handle: Arc::new(RwLock::new(MemoryFSImpl { root: FsNode::new_directory() })) }
+        MemoryFS {
+            handle: Arc::new(RwLock::new(MemoryFSImpl {
+                root: FsNode::new_directory(),
+                locked: HashSet::new(),
+                watchers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Tell this filesystem's watchers that `path` was removed, without
+    /// touching anything here. Used by backends layered on top of a
+    /// `MemoryFS` (like the overlay) that track some removals purely via
+    /// their own bookkeeping and so never go through `MemoryPath::remove_*`.
+    pub(crate) fn notify_removed(&self, path: &str) {
+        notify(&self.handle, path, FsEventKind::Removed);
     }
 }
 
@@ -88,11 +153,13 @@ impl MemoryFS {
 pub struct MemoryFile {
     pub data: DataHandle,
     pub pos: u64,
+    fs: MemoryFSHandle,
+    path: String,
 }
 
 impl Read for MemoryFile {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let n = try!((&self.data.0.write().unwrap().deref()[self.pos as usize..]).read(buf));
+        let n = try!(self.data.read_at(self.pos, buf));
         self.pos += n as u64;
         Ok(n)
     }
@@ -117,6 +184,8 @@ impl Write for MemoryFile {
 
         // Bump us forward
         self.pos = pos + buf.len() as u64;
+        drop(guard);
+        notify(&self.fs, &self.path, FsEventKind::Modified);
         Ok(buf.len())
     }
     fn flush(&mut self) -> Result<()> {
@@ -167,12 +236,26 @@ impl VFS for MemoryFS {
     type PATH = MemoryPath;
     type FILE = MemoryFile;
     type METADATA = MemoryMetadata;
+    type LOCK = MemoryFileLock;
 
     fn path<T: Into<String>>(&self, path: T) -> MemoryPath {
         MemoryPath::new(&self.handle, path.into())
     }
 }
 
+/// An advisory lock on a path within a [`MemoryFS`], released on drop.
+#[derive(Debug)]
+pub struct MemoryFileLock {
+    fs: MemoryFSHandle,
+    path: String,
+}
+
+impl Drop for MemoryFileLock {
+    fn drop(&mut self) {
+        self.fs.write().unwrap().locked.remove(&self.path);
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub struct MemoryPath {
@@ -214,6 +297,40 @@ impl MemoryPath {
     }
 }
 
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    if prefix == "/" || prefix.is_empty() {
+        return true;
+    }
+    path == prefix || path.starts_with(&(prefix.to_owned() + "/"))
+}
+
+fn notify(fs: &MemoryFSHandle, path: &str, kind: FsEventKind) {
+    let mut impl_ = fs.write().unwrap();
+    impl_.watchers.retain(|&(ref prefix, ref sender)| {
+        if !path_has_prefix(path, prefix) {
+            return true;
+        }
+        sender.send(FsEvent { path: path.to_owned(), kind: kind.clone() }).is_ok()
+    });
+}
+
+/// Like `notify`, but for a rename: a watcher is delivered exactly one
+/// `Renamed` event as long as its prefix matches either side of the move,
+/// even if it matches both (e.g. a watcher on a shared ancestor directory).
+fn notify_rename(fs: &MemoryFSHandle, from: &str, to: &str) {
+    let mut impl_ = fs.write().unwrap();
+    impl_.watchers.retain(|&(ref prefix, ref sender)| {
+        if !path_has_prefix(from, prefix) && !path_has_prefix(to, prefix) {
+            return true;
+        }
+        let event = FsEvent {
+            path: from.to_owned(),
+            kind: FsEventKind::Renamed { from: from.to_owned(), to: to.to_owned() },
+        };
+        sender.send(event).is_ok()
+    });
+}
+
 fn traverse_mkdir(node: &mut FsNode, components: &mut Vec<&str>) -> Result<()> {
     if let Some(component) = components.pop() {
         let directory = &mut node.children
@@ -252,6 +369,8 @@ impl VPath for MemoryPath {
         Ok(MemoryFile {
             data: data,
             pos: 0,
+            fs: self.fs.clone(),
+            path: self.path.clone(),
         })
     }
 
@@ -265,13 +384,17 @@ impl VPath for MemoryPath {
             return file_node.data.clone();
         }));
         data.0.write().unwrap().clear();
+        notify(&self.fs, &self.path, FsEventKind::Created);
         Ok(MemoryFile {
             data: data,
             pos: 0,
+            fs: self.fs.clone(),
+            path: self.path.clone(),
         })
     }
 
     fn append(&self) -> Result<MemoryFile> {
+        let existed = self.exists();
         let parent_path = self.parent().unwrap();
         let data = try!(parent_path.with_node(|node| {
             let file_node = node.children
@@ -281,9 +404,14 @@ impl VPath for MemoryPath {
             return file_node.data.clone();
         }));
         let len = data.0.read().unwrap().len();
+        if !existed {
+            notify(&self.fs, &self.path, FsEventKind::Created);
+        }
         Ok(MemoryFile {
             data: data,
             pos: len as u64,
+            fs: self.fs.clone(),
+            path: self.path.clone(),
         })
     }
 
@@ -307,11 +435,15 @@ impl VPath for MemoryPath {
 
 
     fn mkdir(&self) -> Result<()> {
-        let root = &mut self.fs.write().unwrap().root;
-        let mut components: Vec<&str> = self.path.split("/").collect();
-        components.reverse();
-        components.pop();
-        traverse_mkdir(root, &mut components)
+        {
+            let root = &mut self.fs.write().unwrap().root;
+            let mut components: Vec<&str> = self.path.split("/").collect();
+            components.reverse();
+            components.pop();
+            try!(traverse_mkdir(root, &mut components));
+        }
+        notify(&self.fs, &self.path, FsEventKind::Created);
+        Ok(())
     }
 
     fn exists(&self) -> bool {
@@ -332,6 +464,146 @@ impl VPath for MemoryPath {
         return Ok(children);
     }
 
+    fn remove_file(&self, options: RemoveOptions) -> Result<()> {
+        let parent_path = match self.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::new(ErrorKind::Other, "cannot remove root")),
+        };
+        let name = self.file_name().unwrap();
+        let result = parent_path.with_node(|node| {
+            match node.children.get(&name).map(|child| child.kind) {
+                Some(NodeKind::File) => {
+                    node.children.remove(&name);
+                    Ok(())
+                }
+                Some(NodeKind::Directory) => {
+                    Err(Error::new(ErrorKind::Other, format!("{:?} is a directory", name)))
+                }
+                None => Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", name))),
+            }
+        }).and_then(|inner| inner);
+        match result {
+            Ok(()) => {
+                notify(&self.fs, &self.path, FsEventKind::Removed);
+                Ok(())
+            }
+            Err(_) if options.ignore_if_not_exists && !self.exists() => Ok(()),
+            other => other,
+        }
+    }
+
+    fn remove_dir(&self, options: RemoveOptions) -> Result<()> {
+        let parent_path = match self.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::new(ErrorKind::Other, "cannot remove root")),
+        };
+        let name = self.file_name().unwrap();
+        let result = parent_path.with_node(|node| {
+            match node.children.get(&name) {
+                Some(child) if child.kind == NodeKind::Directory => {
+                    if !options.recursive && !child.children.is_empty() {
+                        return Err(Error::new(ErrorKind::Other, format!("{:?} is not empty", name)));
+                    }
+                    node.children.remove(&name);
+                    Ok(())
+                }
+                Some(_) => Err(Error::new(ErrorKind::Other, format!("{:?} is not a directory", name))),
+                None => Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", name))),
+            }
+        }).and_then(|inner| inner);
+        match result {
+            Ok(()) => {
+                notify(&self.fs, &self.path, FsEventKind::Removed);
+                Ok(())
+            }
+            Err(_) if options.ignore_if_not_exists && !self.exists() => Ok(()),
+            other => other,
+        }
+    }
+
+    fn rename(&self, dest: &MemoryPath, options: RenameOptions) -> Result<()> {
+        let src_parent = match self.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::new(ErrorKind::Other, "cannot rename root")),
+        };
+        let src_name = self.file_name().unwrap();
+        let dest_parent = match dest.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::new(ErrorKind::Other, "cannot rename onto root")),
+        };
+        let dest_name = dest.file_name().unwrap();
+
+        if !options.overwrite && dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", dest.path)));
+        }
+
+        if dest.path == self.path || path_has_prefix(&dest.path, &self.path) {
+            // Detaching `self` before re-inserting under `dest_parent` would
+            // otherwise remove the very subtree `dest_parent`'s traversal
+            // path lives inside, turning a rejected rename into silent data
+            // loss instead of a clean error.
+            return Err(Error::new(ErrorKind::Other,
+                                  format!("cannot rename {:?} into its own subtree {:?}", self.path, dest.path)));
+        }
+
+        let detached = try!(src_parent.with_node(|parent_node| {
+            match parent_node.children.remove(&src_name) {
+                Some(child) => Ok(child),
+                None => Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", src_name))),
+            }
+        }).and_then(|inner| inner));
+
+        try!(dest_parent.with_node(move |parent_node| {
+            parent_node.children.insert(dest_name, detached);
+        }));
+        notify_rename(&self.fs, &self.path, &dest.path);
+        Ok(())
+    }
+
+    fn copy_file(&self, dest: &MemoryPath, options: CopyOptions) -> Result<()> {
+        if !options.overwrite && dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", dest.path)));
+        }
+
+        let bytes = try!(self.with_node(|node| {
+            if node.kind != NodeKind::File {
+                return Err(Error::new(ErrorKind::Other, "cannot copy a directory"));
+            }
+            Ok(node.data.0.read().unwrap().clone())
+        }).and_then(|inner| inner));
+
+        let dest_parent = match dest.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::new(ErrorKind::Other, "cannot copy onto root")),
+        };
+        let dest_name = dest.file_name().unwrap();
+
+        try!(dest_parent.with_node(move |parent_node| {
+            let mut file_node = FsNode::new_file();
+            *file_node.data.0.write().unwrap() = bytes;
+            parent_node.children.insert(dest_name, file_node);
+        }));
+        notify(&self.fs, &dest.path, FsEventKind::Created);
+        Ok(())
+    }
+
+    fn lock_file(&self) -> Result<MemoryFileLock> {
+        let mut fs = self.fs.write().unwrap();
+        if !fs.locked.insert(self.path.clone()) {
+            return Err(Error::new(ErrorKind::WouldBlock, format!("{:?} is already locked", self.path)));
+        }
+        Ok(MemoryFileLock {
+            fs: self.fs.clone(),
+            path: self.path.clone(),
+        })
+    }
+
+    fn watch(&self) -> Receiver<FsEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.fs.write().unwrap().watchers.push((self.path.clone(), tx));
+        rx
+    }
+
 }
 
 
@@ -356,7 +628,7 @@ mod tests {
 
     use super::*;
     use VPath;
-    use vfs::{VFS, VMetadata};
+    use vfs::{VFS, VMetadata, RemoveOptions, RenameOptions, CopyOptions, FsEventKind};
 
     #[test]
     fn mkdir() {
@@ -426,6 +698,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_at_write_at() {
+        let data = DataHandle::new();
+        assert_eq!(data.write_at(0, b"hello world").unwrap(), 11);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(data.read_at(6, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"world");
+
+        // Reading past the end is clamped rather than erroring.
+        assert_eq!(data.read_at(100, &mut buf).unwrap(), 0);
+
+        // write_at can punch a hole past the current end, zero-filling it.
+        data.write_at(20, b"!").unwrap();
+        let mut tail = [0u8; 9];
+        data.read_at(11, &mut tail).unwrap();
+        assert_eq!(&tail, b"\0\0\0\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn concurrent_readers_same_file() {
+        let fs = MemoryFS::new();
+        let path = fs.path("/foobar.txt");
+        {
+            let mut file = path.create().unwrap();
+            write!(file, "hello world").unwrap();
+        }
+        // Two independent handles over the same underlying data, each with
+        // its own cursor, can both make progress without blocking on a lock
+        // held for the duration of a read.
+        let mut a = path.open().unwrap();
+        let mut b = path.open().unwrap();
+        let mut buf_a = [0u8; 5];
+        let mut buf_b = [0u8; 5];
+        a.read_exact(&mut buf_a).unwrap();
+        b.seek(SeekFrom::Start(6)).unwrap();
+        b.read_exact(&mut buf_b).unwrap();
+        assert_eq!(&buf_a, b"hello");
+        assert_eq!(&buf_b, b"world");
+    }
+
     #[test]
     fn append() {
         let fs = MemoryFS::new();
@@ -492,5 +805,158 @@ mod tests {
         assert_eq!(entries, vec!["/foo/bar".to_owned(), "/foo/baz".to_owned()]);
     }
 
+    #[test]
+    fn remove_file() {
+        let fs = MemoryFS::new();
+        let path = fs.path("/foobar.txt");
+        path.create().unwrap();
+        assert!(path.exists());
+        path.remove_file(RemoveOptions::default()).unwrap();
+        assert!(!path.exists());
+        assert!(path.remove_file(RemoveOptions::default()).is_err());
+        path.remove_file(RemoveOptions { ignore_if_not_exists: true, ..RemoveOptions::default() }).unwrap();
+    }
+
+    #[test]
+    fn remove_dir() {
+        let fs = MemoryFS::new();
+        let path = fs.path("/foo");
+        let child = fs.path("/foo/bar");
+        child.mkdir().unwrap();
+        assert!(path.remove_dir(RemoveOptions::default()).is_err(), "should refuse to remove a non-empty dir");
+        path.remove_dir(RemoveOptions { recursive: true, ..RemoveOptions::default() }).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rename() {
+        let fs = MemoryFS::new();
+        let src = fs.path("/foo.txt");
+        let dest = fs.path("/bar.txt");
+        {
+            let mut file = src.create().unwrap();
+            write!(file, "hello").unwrap();
+        }
+        src.rename(&dest, RenameOptions::default()).unwrap();
+        assert!(!src.exists());
+        let mut string = String::new();
+        dest.open().unwrap().read_to_string(&mut string).unwrap();
+        assert_eq!(string, "hello");
+    }
+
+    #[test]
+    fn rename_into_own_subtree_is_rejected() {
+        let fs = MemoryFS::new();
+        fs.path("/foo").mkdir().unwrap();
+        fs.path("/foo/a.txt").create().unwrap();
+
+        // Renaming a directory into a path nested under itself must be
+        // rejected up front: detaching `/foo` before the failed re-insert
+        // would otherwise drop the whole subtree on the floor.
+        assert!(fs.path("/foo").rename(&fs.path("/foo/sub"), RenameOptions::default()).is_err());
+        assert!(fs.path("/foo").exists());
+        assert!(fs.path("/foo/a.txt").exists());
+    }
+
+    #[test]
+    fn rename_notifies_destination_watcher() {
+        let fs = MemoryFS::new();
+        fs.path("/src_dir").mkdir().unwrap();
+        fs.path("/dest_dir").mkdir().unwrap();
+        fs.path("/src_dir/a.txt").create().unwrap();
+
+        // A watcher on the destination directory should see the file show
+        // up there too, not just a watcher on the source.
+        let events = fs.path("/dest_dir").watch();
+        fs.path("/src_dir/a.txt").rename(&fs.path("/dest_dir/a.txt"), RenameOptions::default()).unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Renamed { .. } => {}
+            other => panic!("expected Renamed, got {:?}", other),
+        }
+        assert!(events.try_recv().is_err(), "watcher should not be double-notified");
+    }
+
+    #[test]
+    fn rename_notifies_shared_ancestor_watcher_once() {
+        let fs = MemoryFS::new();
+        fs.path("/src_dir").mkdir().unwrap();
+        fs.path("/dest_dir").mkdir().unwrap();
+        fs.path("/src_dir/a.txt").create().unwrap();
+
+        // A watcher on a common ancestor of both sides of the rename should
+        // still see exactly one event, not one per matching path.
+        let events = fs.path("/").watch();
+        fs.path("/src_dir/a.txt").rename(&fs.path("/dest_dir/a.txt"), RenameOptions::default()).unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Renamed { .. } => {}
+            other => panic!("expected Renamed, got {:?}", other),
+        }
+        assert!(events.try_recv().is_err(), "watcher should not be double-notified");
+    }
+
+    #[test]
+    fn copy_file() {
+        let fs = MemoryFS::new();
+        let src = fs.path("/foo.txt");
+        let dest = fs.path("/bar.txt");
+        {
+            let mut file = src.create().unwrap();
+            write!(file, "hello").unwrap();
+        }
+        src.copy_file(&dest, CopyOptions::default()).unwrap();
+        assert!(src.exists());
+        let mut string = String::new();
+        dest.open().unwrap().read_to_string(&mut string).unwrap();
+        assert_eq!(string, "hello");
+
+        // The copy should not alias the original's buffer.
+        write!(src.open().unwrap(), "HELLO").unwrap();
+        let mut string = String::new();
+        dest.open().unwrap().read_to_string(&mut string).unwrap();
+        assert_eq!(string, "hello");
+    }
+
+    #[test]
+    fn lock_file() {
+        let fs = MemoryFS::new();
+        let path = fs.path("/foo.txt");
+        path.create().unwrap();
+
+        let lock = path.lock_file().unwrap();
+        assert!(path.lock_file().is_err(), "a second lock should be refused");
+        drop(lock);
+        path.lock_file().unwrap();
+    }
+
+    #[test]
+    fn watch() {
+        let fs = MemoryFS::new();
+        let dir = fs.path("/foo");
+        dir.mkdir().unwrap();
+        let path = fs.path("/foo/bar.txt");
+        let events = dir.watch();
+
+        path.create().unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Created => {}
+            other => panic!("expected Created, got {:?}", other),
+        }
+
+        write!(path.open().unwrap(), "hi").unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Modified => {}
+            other => panic!("expected Modified, got {:?}", other),
+        }
+
+        path.remove_file(RemoveOptions::default()).unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Removed => {}
+            other => panic!("expected Removed, got {:?}", other),
+        }
+
+        // Events outside the watched subtree are not delivered.
+        fs.path("/elsewhere.txt").create().unwrap();
+        assert!(events.try_recv().is_err());
+    }
 
 }
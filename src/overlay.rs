@@ -0,0 +1,587 @@
+//! A copy-on-write overlay VFS: a writable [`MemoryFS`] layered on top of an
+//! arbitrary read-only (or read-mostly) lower `VFS`.
+//!
+//! Lookups check the upper layer first and fall through to the lower layer.
+//! Any write touches only the upper layer: `create`/`append` "copy up" the
+//! file's current bytes into the upper `MemoryFS` before handing back a
+//! writable handle, and removing a file that only exists below records a
+//! whiteout so it no longer appears to exist, without disturbing the lower
+//! layer itself.
+
+use std::fmt;
+use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::Receiver;
+use std::collections::HashSet;
+
+use vfs::{VFS, VPath, VMetadata, RemoveOptions, RenameOptions, CopyOptions, FsEvent, FsEventKind};
+use memory::{MemoryFS, MemoryFile, MemoryMetadata, MemoryFileLock};
+
+/// Whether `path` is `prefix` itself or lives somewhere underneath it.
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    path == prefix || path.starts_with(&(prefix.to_owned() + "/"))
+}
+
+struct OverlayFSImpl<L: VFS + 'static> {
+    lower: L,
+    upper: MemoryFS,
+    whiteouts: HashSet<String>,
+}
+
+type OverlayHandle<L> = Arc<RwLock<OverlayFSImpl<L>>>;
+
+/// A writable overlay on top of a read-only `lower` filesystem.
+pub struct OverlayFS<L: VFS + 'static> {
+    handle: OverlayHandle<L>,
+}
+
+impl<L: VFS + 'static> OverlayFS<L> {
+    pub fn new(lower: L) -> OverlayFS<L> {
+        OverlayFS {
+            handle: Arc::new(RwLock::new(OverlayFSImpl {
+                lower: lower,
+                upper: MemoryFS::new(),
+                whiteouts: HashSet::new(),
+            })),
+        }
+    }
+}
+
+pub struct OverlayPath<L: VFS + 'static> {
+    path: String,
+    fs: OverlayHandle<L>,
+}
+
+impl<L: VFS + 'static> Clone for OverlayPath<L> {
+    fn clone(&self) -> Self {
+        OverlayPath { path: self.path.clone(), fs: self.fs.clone() }
+    }
+}
+
+impl<L: VFS + 'static> PartialEq for OverlayPath<L> {
+    fn eq(&self, other: &OverlayPath<L>) -> bool {
+        self.path == other.path
+    }
+}
+
+impl<L: VFS + 'static> fmt::Debug for OverlayPath<L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OverlayPath({:?})", self.path)
+    }
+}
+
+impl<L: VFS + 'static> OverlayPath<L> {
+    fn is_whiteout(&self) -> bool {
+        self.fs.read().unwrap().whiteouts.contains(&self.path)
+    }
+
+    fn upper_path(&self) -> <MemoryFS as VFS>::PATH {
+        self.fs.read().unwrap().upper.path(self.path.clone())
+    }
+
+    fn lower_path(&self) -> L::PATH {
+        self.fs.read().unwrap().lower.path(self.path.clone())
+    }
+
+    fn decompose_path(&self) -> (Option<String>, String) {
+        let mut split = self.path.rsplitn(2, "/");
+        if let Some(mut filename) = split.next() {
+            if let Some(mut parent) = split.next() {
+                if parent.is_empty() {
+                    parent = "/";
+                }
+                if filename.is_empty() {
+                    filename = parent;
+                    return (None, filename.to_owned());
+                }
+                return (Some(parent.to_owned()), filename.to_owned());
+            }
+        }
+        (None, self.path.clone())
+    }
+
+    /// Make sure this path's parent directory exists in the upper layer,
+    /// creating it and any missing ancestors there; the lower layer is not
+    /// consulted.
+    fn ensure_upper_parent(&self) -> Result<()> {
+        match self.parent() {
+            Some(parent) => parent.upper_path().mkdir(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<L: VFS + 'static> VPath for OverlayPath<L> {
+    type FS = OverlayFS<L>;
+
+    fn open(&self) -> Result<OverlayFile<L>> {
+        if self.is_whiteout() {
+            return Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)));
+        }
+        let upper_path = self.upper_path();
+        if upper_path.exists() {
+            return upper_path.open().map(OverlayFile::Upper);
+        }
+        self.lower_path().open().map(OverlayFile::Lower)
+    }
+
+    fn create(&self) -> Result<OverlayFile<L>> {
+        try!(self.ensure_upper_parent());
+        self.fs.write().unwrap().whiteouts.remove(&self.path);
+        self.upper_path().create().map(OverlayFile::Upper)
+    }
+
+    fn append(&self) -> Result<OverlayFile<L>> {
+        try!(self.ensure_upper_parent());
+        let upper_path = self.upper_path();
+        if !upper_path.exists() && !self.is_whiteout() {
+            let lower_path = self.lower_path();
+            if lower_path.exists() {
+                let mut bytes = Vec::new();
+                let mut lower_file = try!(lower_path.open());
+                try!(lower_file.read_to_end(&mut bytes));
+                let mut file = try!(upper_path.create());
+                try!(file.write_all(&bytes));
+            }
+        }
+        self.fs.write().unwrap().whiteouts.remove(&self.path);
+        upper_path.append().map(OverlayFile::Upper)
+    }
+
+    fn parent(&self) -> Option<OverlayPath<L>> {
+        self.decompose_path().0.map(|parent| OverlayPath { path: parent, fs: self.fs.clone() })
+    }
+
+    fn file_name(&self) -> Option<String> {
+        Some(self.decompose_path().1)
+    }
+
+    fn push<'a, T: Into<&'a str>>(&mut self, path: T) {
+        if !self.path.ends_with('/') {
+            self.path.push_str("/");
+        }
+        self.path.push_str(&path.into());
+    }
+
+    fn mkdir(&self) -> Result<()> {
+        self.fs.write().unwrap().whiteouts.remove(&self.path);
+        self.upper_path().mkdir()
+    }
+
+    fn exists(&self) -> bool {
+        if self.is_whiteout() {
+            return false;
+        }
+        self.upper_path().exists() || self.lower_path().exists()
+    }
+
+    fn metadata(&self) -> Result<OverlayMetadata<L>> {
+        if self.is_whiteout() {
+            return Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)));
+        }
+        let upper_path = self.upper_path();
+        if upper_path.exists() {
+            return upper_path.metadata().map(OverlayMetadata::Upper);
+        }
+        self.lower_path().metadata().map(OverlayMetadata::Lower)
+    }
+
+    fn read_dir(&self) -> Result<Box<Iterator<Item = Result<OverlayPath<L>>>>> {
+        if self.is_whiteout() {
+            return Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)));
+        }
+        let upper_path = self.upper_path();
+        let lower_path = self.lower_path();
+        let upper_exists = upper_path.exists();
+        let lower_exists = lower_path.exists();
+        if !upper_exists && !lower_exists {
+            return Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)));
+        }
+
+        let mut names: Vec<String> = Vec::new();
+        if upper_exists {
+            for entry in try!(upper_path.read_dir()) {
+                if let Some(name) = try!(entry).file_name() {
+                    names.push(name);
+                }
+            }
+        }
+        if lower_exists {
+            for entry in try!(lower_path.read_dir()) {
+                if let Some(name) = try!(entry).file_name() {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+
+        let base = self.path.clone();
+        let fs = self.fs.clone();
+        let whiteouts = self.fs.read().unwrap().whiteouts.clone();
+        let entries: Vec<_> = names.into_iter()
+                                    .map(|name| base.clone() + "/" + &name)
+                                    .filter(|full_path| !whiteouts.contains(full_path))
+                                    .map(|full_path| Ok(OverlayPath { path: full_path, fs: fs.clone() }))
+                                    .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    fn remove_file(&self, options: RemoveOptions) -> Result<()> {
+        if !self.exists() {
+            return if options.ignore_if_not_exists {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)))
+            };
+        }
+        let existed_upper = self.upper_path().exists();
+        let _ = self.upper_path().remove_file(RemoveOptions { ignore_if_not_exists: true, ..options });
+        self.fs.write().unwrap().whiteouts.insert(self.path.clone());
+        if !existed_upper {
+            // Nothing existed upper, so the upper `MemoryFS`'s own notify
+            // above never fired for this removal; the whiteout we just
+            // recorded is the only thing that makes the file disappear, so
+            // tell its watchers ourselves.
+            self.fs.read().unwrap().upper.notify_removed(&self.path);
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, options: RemoveOptions) -> Result<()> {
+        if !self.exists() {
+            return if options.ignore_if_not_exists {
+                Ok(())
+            } else {
+                Err(Error::new(ErrorKind::NotFound, format!("File not found {:?}", self.path)))
+            };
+        }
+        let mut entries = try!(self.read_dir());
+        if options.recursive {
+            // Whiteout every descendant too, not just this directory itself,
+            // so a file that only ever existed in the lower layer doesn't
+            // keep appearing to exist when addressed directly.
+            for entry in entries {
+                let entry = try!(entry);
+                if try!(entry.metadata()).is_dir() {
+                    try!(entry.remove_dir(RemoveOptions { recursive: true, ..options }));
+                } else {
+                    try!(entry.remove_file(RemoveOptions { ignore_if_not_exists: true, ..options }));
+                }
+            }
+        } else if entries.next().is_some() {
+            return Err(Error::new(ErrorKind::Other, format!("{:?} is not empty", self.path)));
+        }
+        let existed_upper = self.upper_path().exists();
+        let _ = self.upper_path().remove_dir(RemoveOptions { recursive: true, ignore_if_not_exists: true, ..options });
+        self.fs.write().unwrap().whiteouts.insert(self.path.clone());
+        if !existed_upper {
+            // As in `remove_file`: a directory that only ever existed below
+            // never touches the upper `MemoryFS`, so nothing would notify
+            // its watchers without us doing it explicitly.
+            self.fs.read().unwrap().upper.notify_removed(&self.path);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, dest: &OverlayPath<L>, options: RenameOptions) -> Result<()> {
+        if !options.overwrite && dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", dest.path)));
+        }
+        if try!(self.metadata()).is_dir() {
+            if dest.path == self.path || path_has_prefix(&dest.path, &self.path) {
+                // `dest` would live inside `self`: the loop below would move
+                // the children under `dest`, and the final removal and
+                // whiteout of `self` would then take them with it. Reject
+                // the nested case outright.
+                return Err(Error::new(ErrorKind::Other,
+                                      format!("cannot rename {:?} into its own subtree {:?}", self.path, dest.path)));
+            }
+            // Snapshot the children before creating `dest`, so a sibling
+            // rename never sees `dest` show up as one of its own entries.
+            let entries = try!(try!(self.read_dir()).collect::<Result<Vec<_>>>());
+            try!(dest.mkdir());
+            for entry in entries {
+                let name = entry.file_name().unwrap();
+                let mut dest_child = dest.clone();
+                dest_child.push(name.as_str());
+                try!(entry.rename(&dest_child, options));
+            }
+            let _ = self.upper_path().remove_dir(RemoveOptions { recursive: true, ignore_if_not_exists: true, ..RemoveOptions::default() });
+            self.fs.write().unwrap().whiteouts.insert(self.path.clone());
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        let mut src_file = try!(self.open());
+        try!(src_file.read_to_end(&mut bytes));
+        try!(dest.ensure_upper_parent());
+        {
+            let mut file = try!(dest.upper_path().create());
+            try!(file.write_all(&bytes));
+        }
+        {
+            let mut impl_ = self.fs.write().unwrap();
+            impl_.whiteouts.remove(&dest.path);
+            impl_.whiteouts.insert(self.path.clone());
+        }
+        let _ = self.upper_path().remove_file(RemoveOptions { ignore_if_not_exists: true, ..RemoveOptions::default() });
+        Ok(())
+    }
+
+    fn copy_file(&self, dest: &OverlayPath<L>, options: CopyOptions) -> Result<()> {
+        if try!(self.metadata()).is_dir() {
+            return Err(Error::new(ErrorKind::Other, "cannot copy a directory"));
+        }
+        if !options.overwrite && dest.exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", dest.path)));
+        }
+        let mut bytes = Vec::new();
+        let mut src_file = try!(self.open());
+        try!(src_file.read_to_end(&mut bytes));
+        try!(dest.ensure_upper_parent());
+        self.fs.write().unwrap().whiteouts.remove(&dest.path);
+        let mut file = try!(dest.upper_path().create());
+        file.write_all(&bytes)
+    }
+
+    fn lock_file(&self) -> Result<MemoryFileLock> {
+        // Locking is purely advisory bookkeeping, so delegating to the upper
+        // `MemoryFS` is enough; it doesn't matter that the file itself might
+        // still only exist in the lower layer.
+        self.upper_path().lock_file()
+    }
+
+    fn watch(&self) -> Receiver<FsEvent> {
+        // Watching the upper `MemoryFS` covers every write (copy-up makes
+        // sure of that) and removals of anything that was ever copied up;
+        // `remove_file`/`remove_dir` additionally call `notify_removed`
+        // directly for paths that only ever lived in the lower layer, since
+        // those never touch the upper FS at all. Changes made directly to
+        // the lower layer, bypassing this overlay, are still invisible
+        // either way.
+        self.upper_path().watch()
+    }
+}
+
+impl<L: VFS + 'static> VFS for OverlayFS<L> {
+    type PATH = OverlayPath<L>;
+    type FILE = OverlayFile<L>;
+    type METADATA = OverlayMetadata<L>;
+    type LOCK = MemoryFileLock;
+
+    fn path<T: Into<String>>(&self, path: T) -> OverlayPath<L> {
+        OverlayPath { path: path.into(), fs: self.handle.clone() }
+    }
+}
+
+/// A file handle from either layer of an [`OverlayFS`].
+pub enum OverlayFile<L: VFS + 'static> {
+    Upper(MemoryFile),
+    Lower(L::FILE),
+}
+
+impl<L: VFS + 'static> Read for OverlayFile<L> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            OverlayFile::Upper(ref mut file) => file.read(buf),
+            OverlayFile::Lower(ref mut file) => file.read(buf),
+        }
+    }
+}
+
+impl<L: VFS + 'static> Write for OverlayFile<L> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            OverlayFile::Upper(ref mut file) => file.write(buf),
+            OverlayFile::Lower(ref mut file) => file.write(buf),
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            OverlayFile::Upper(ref mut file) => file.flush(),
+            OverlayFile::Lower(ref mut file) => file.flush(),
+        }
+    }
+}
+
+impl<L: VFS + 'static> Seek for OverlayFile<L> {
+    fn seek(&mut self, style: SeekFrom) -> Result<u64> {
+        match *self {
+            OverlayFile::Upper(ref mut file) => file.seek(style),
+            OverlayFile::Lower(ref mut file) => file.seek(style),
+        }
+    }
+}
+
+/// Metadata from either layer of an [`OverlayFS`].
+pub enum OverlayMetadata<L: VFS + 'static> {
+    Upper(MemoryMetadata),
+    Lower(L::METADATA),
+}
+
+impl<L: VFS + 'static> VMetadata for OverlayMetadata<L> {
+    fn is_dir(&self) -> bool {
+        match *self {
+            OverlayMetadata::Upper(ref meta) => meta.is_dir(),
+            OverlayMetadata::Lower(ref meta) => meta.is_dir(),
+        }
+    }
+    fn is_file(&self) -> bool {
+        match *self {
+            OverlayMetadata::Upper(ref meta) => meta.is_file(),
+            OverlayMetadata::Lower(ref meta) => meta.is_file(),
+        }
+    }
+    fn len(&self) -> u64 {
+        match *self {
+            OverlayMetadata::Upper(ref meta) => meta.len(),
+            OverlayMetadata::Lower(ref meta) => meta.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use VPath;
+    use vfs::{VFS, RemoveOptions, RenameOptions};
+    use memory::MemoryFS;
+
+    fn lower_with_file(path: &str, contents: &str) -> MemoryFS {
+        let lower = MemoryFS::new();
+        let mut file = lower.path(path).create().unwrap();
+        write!(file, "{}", contents).unwrap();
+        lower
+    }
+
+    #[test]
+    fn reads_fall_through_to_lower() {
+        let lower = lower_with_file("/foo.txt", "hello");
+        let overlay = OverlayFS::new(lower);
+
+        let mut contents = String::new();
+        overlay.path("/foo.txt").open().unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn write_copies_up_without_touching_lower() {
+        let lower = lower_with_file("/foo.txt", "hello");
+        // Keep a path into the lower FS's own handle before it's moved into
+        // the overlay, so we can still check it wasn't mutated afterwards.
+        let original_path = lower.path("/foo.txt");
+        let overlay = OverlayFS::new(lower);
+
+        write!(overlay.path("/foo.txt").append().unwrap(), " world").unwrap();
+
+        let mut overlaid = String::new();
+        overlay.path("/foo.txt").open().unwrap().read_to_string(&mut overlaid).unwrap();
+        assert_eq!(overlaid, "hello world");
+
+        let mut original = String::new();
+        original_path.open().unwrap().read_to_string(&mut original).unwrap();
+        assert_eq!(original, "hello");
+    }
+
+    #[test]
+    fn remove_whites_out_lower_file() {
+        let lower = lower_with_file("/foo.txt", "hello");
+        let overlay = OverlayFS::new(lower);
+
+        let path = overlay.path("/foo.txt");
+        assert!(path.exists());
+        path.remove_file(RemoveOptions::default()).unwrap();
+        assert!(!path.exists());
+        assert!(path.open().is_err());
+    }
+
+    #[test]
+    fn remove_notifies_watcher_for_lower_only_file() {
+        let lower = lower_with_file("/foo.txt", "hello");
+        let overlay = OverlayFS::new(lower);
+
+        // This file only exists in the lower layer, so removing it never
+        // touches the upper `MemoryFS` at all; the overlay must still tell
+        // watchers it's gone.
+        let events = overlay.path("/").watch();
+        overlay.path("/foo.txt").remove_file(RemoveOptions::default()).unwrap();
+        match events.recv().unwrap().kind {
+            FsEventKind::Removed => {}
+            other => panic!("expected Removed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_dir_merges_layers() {
+        let lower = MemoryFS::new();
+        lower.path("/dir").mkdir().unwrap();
+        lower.path("/dir/lower.txt").create().unwrap();
+        let overlay = OverlayFS::new(lower);
+        overlay.path("/dir/upper.txt").create().unwrap();
+
+        let mut names: Vec<String> = overlay.path("/dir")
+                                             .read_dir()
+                                             .unwrap()
+                                             .map(|entry| entry.unwrap().file_name().unwrap())
+                                             .collect();
+        names.sort();
+        assert_eq!(names, vec!["lower.txt".to_owned(), "upper.txt".to_owned()]);
+    }
+
+    #[test]
+    fn recursive_remove_dir_whites_out_descendants() {
+        let lower = MemoryFS::new();
+        lower.path("/dir").mkdir().unwrap();
+        lower.path("/dir/a.txt").create().unwrap();
+        let overlay = OverlayFS::new(lower);
+
+        overlay.path("/dir").remove_dir(RemoveOptions { recursive: true, ..RemoveOptions::default() }).unwrap();
+
+        assert!(!overlay.path("/dir").exists());
+        // A child that only ever existed in the lower layer must not remain
+        // visible just because it wasn't whited out individually.
+        assert!(!overlay.path("/dir/a.txt").exists());
+    }
+
+    #[test]
+    fn rename_directory_moves_lower_only_contents() {
+        let lower = MemoryFS::new();
+        lower.path("/foo").mkdir().unwrap();
+        let mut file = lower.path("/foo/bar.txt").create().unwrap();
+        write!(file, "hello").unwrap();
+        let overlay = OverlayFS::new(lower);
+
+        overlay.path("/foo").rename(&overlay.path("/moved"), RenameOptions::default()).unwrap();
+
+        assert!(!overlay.path("/foo").exists());
+        let mut contents = String::new();
+        overlay.path("/moved/bar.txt").open().unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn rename_directory_into_own_subtree_is_rejected() {
+        let lower = MemoryFS::new();
+        lower.path("/foo").mkdir().unwrap();
+        lower.path("/foo/bar.txt").create().unwrap();
+        let overlay = OverlayFS::new(lower);
+
+        // Must fail outright: otherwise `bar.txt` would be moved under
+        // `/foo/sub` and then lost when `/foo` itself is removed and
+        // whited out.
+        assert!(overlay.path("/foo").rename(&overlay.path("/foo/sub"), RenameOptions::default()).is_err());
+        assert!(overlay.path("/foo").exists());
+        assert!(overlay.path("/foo/bar.txt").exists());
+    }
+
+    #[test]
+    fn copy_file_rejects_directory() {
+        let lower = MemoryFS::new();
+        lower.path("/dir").mkdir().unwrap();
+        let overlay = OverlayFS::new(lower);
+
+        assert!(overlay.path("/dir").copy_file(&overlay.path("/dir2"), Default::default()).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! The core traits of the crate: [`VFS`], a handle to a filesystem that mints
+//! paths, and [`VPath`], a path within one that knows how to open, create and
+//! list itself. Everything else in this crate is a backend implementing both.
+
+use std::fmt::Debug;
+use std::io::{Read, Write, Seek, Result};
+use std::sync::mpsc::Receiver;
+
+/// A filesystem backend capable of producing paths rooted within it.
+pub trait VFS {
+    type PATH: VPath<FS = Self>;
+    type FILE: Read + Write + Seek;
+    type METADATA: VMetadata;
+    type LOCK;
+
+    /// Create a new path within this filesystem
+    fn path<T: Into<String>>(&self, path: T) -> Self::PATH;
+}
+
+/// An abstract path within a [`VFS`]
+pub trait VPath: Debug + Clone + PartialEq + Sized {
+    type FS: VFS;
+
+    /// Open the file at this path for reading
+    fn open(&self) -> Result<<Self::FS as VFS>::FILE>;
+    /// Open the file at this path for writing, truncating any existing content
+    fn create(&self) -> Result<<Self::FS as VFS>::FILE>;
+    /// Open the file at this path for appending, creating it if necessary
+    fn append(&self) -> Result<<Self::FS as VFS>::FILE>;
+    /// The parent of this path, or `None` if this path is the root
+    fn parent(&self) -> Option<Self>;
+    /// The final component of this path
+    fn file_name(&self) -> Option<String>;
+    /// Append a path segment onto this path in place
+    fn push<'a, T: Into<&'a str>>(&mut self, path: T);
+    /// Create this path as a directory, including any missing parents
+    fn mkdir(&self) -> Result<()>;
+    /// Whether an entry currently exists at this path
+    fn exists(&self) -> bool;
+    /// Metadata about the entry at this path
+    fn metadata(&self) -> Result<<Self::FS as VFS>::METADATA>;
+    /// Iterate the entries of this path, which must be a directory
+    fn read_dir(&self) -> Result<Box<Iterator<Item = Result<Self>>>>;
+
+    /// Remove the file at this path
+    fn remove_file(&self, options: RemoveOptions) -> Result<()>;
+    /// Remove the directory at this path; fails if it has children unless
+    /// `options.recursive` is set
+    fn remove_dir(&self, options: RemoveOptions) -> Result<()>;
+    /// Move this path to `dest`, which need not be in the same directory
+    fn rename(&self, dest: &Self, options: RenameOptions) -> Result<()>;
+    /// Copy the file at this path to `dest`
+    fn copy_file(&self, dest: &Self, options: CopyOptions) -> Result<()>;
+
+    /// Acquire an exclusive advisory lock on this path. A second `lock_file`
+    /// on an already-locked path fails with an error; the lock is released
+    /// when the returned guard is dropped.
+    fn lock_file(&self) -> Result<<Self::FS as VFS>::LOCK>;
+
+    /// Start watching this path, and anything below it, for changes.
+    /// Mutations are delivered as [`FsEvent`]s on the returned channel until
+    /// it is dropped.
+    fn watch(&self) -> Receiver<FsEvent>;
+}
+
+/// Options controlling [`VPath::remove_file`] and [`VPath::remove_dir`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RemoveOptions {
+    /// For directories, remove non-empty children too instead of failing
+    pub recursive: bool,
+    /// Succeed even if nothing exists at the path
+    pub ignore_if_not_exists: bool,
+}
+
+/// Options controlling [`VPath::rename`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenameOptions {
+    /// Replace the destination if it already exists, instead of failing
+    pub overwrite: bool,
+}
+
+/// Options controlling [`VPath::copy_file`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions {
+    /// Replace the destination if it already exists, instead of failing
+    pub overwrite: bool,
+}
+
+/// Metadata about the entry at a [`VPath`]
+pub trait VMetadata {
+    /// Is this entry a directory?
+    fn is_dir(&self) -> bool;
+    /// Is this entry a file?
+    fn is_file(&self) -> bool;
+    /// The length of the entry, in bytes; 0 for directories
+    fn len(&self) -> u64;
+}
+
+/// A single change delivered to a [`VPath::watch`] subscriber
+#[derive(Debug, Clone)]
+pub struct FsEvent {
+    /// The path the change happened at
+    pub path: String,
+    pub kind: FsEventKind,
+}
+
+/// The kind of change an [`FsEvent`] describes
+#[derive(Debug, Clone)]
+pub enum FsEventKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed { from: String, to: String },
+}